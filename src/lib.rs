@@ -1,3 +1,4 @@
+#![feature(allocator_api)]
 //! Extend Vec to allow reference to content while pushing new elements.
 //!
 //! This is like `slice::split_at_mut` but instead of splitting into two
@@ -29,16 +30,29 @@
 //! assert_eq!(vec, &[1, 2, 3, 4, 4, 1, 2, 3, 4]);
 //! ```
 
+use std::alloc::Allocator;
+use std::alloc::Global;
+use std::collections::TryReserveError;
 use std::convert::AsMut;
 use std::convert::AsRef;
+use std::error::Error;
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::ops::Bound;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::ops::RangeBounds;
+use std::ptr;
 use std::slice;
 
 /// Allows pushing to a Vec while keeping a reference to it's content.
 pub trait AsFixedCapacityVec {
     type Item;
 
+    /// The allocator backing `Self`, carried through to the [`FixedCapacityVec`] produced by
+    /// this trait's methods.
+    type Alloc: Allocator;
+
     /// Split a vec to create an initialized "read" view and an extendable "write" view
     ///
     /// Allow extending a Vec while keeping a reference to the previous content. The "read" view
@@ -64,32 +78,97 @@ pub trait AsFixedCapacityVec {
     /// }
     /// assert_eq!(vec, &[1, 2, 1, 2, 1, 2]);
     /// ```
+    #[allow(clippy::type_complexity)]
     fn with_fixed_capacity(
         &mut self,
         capacity: usize,
-    ) -> (&mut [Self::Item], FixedCapacityVec<Self::Item>);
+    ) -> (&mut [Self::Item], FixedCapacityVec<Self::Item, Self::Alloc>);
+
+    /// Like [`with_fixed_capacity`](Self::with_fixed_capacity), but reports allocation failure
+    /// instead of panicking.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if reserving room for `capacity` more elements in the underlying `Vec`
+    /// fails.
+    #[allow(clippy::type_complexity)]
+    fn try_with_capacity(
+        &mut self,
+        capacity: usize,
+    ) -> Result<(&mut [Self::Item], FixedCapacityVec<Self::Item, Self::Alloc>), TryExtendError>;
 }
 
 /// A safe wrapper around a Vec which is not allowed to reallocate
 #[derive(Debug)]
-pub struct FixedCapacityVec<'a, T>
+pub struct FixedCapacityVec<'a, T, A = Global>
 where
     T: 'a,
+    A: Allocator,
 {
     start: usize,
     max_len: usize,
-    buffer: &'a mut Vec<T>,
+    buffer: &'a mut Vec<T, A>,
+}
+
+/// Error returned by [`AsFixedCapacityVec::try_with_capacity`] and
+/// [`FixedCapacityVec::try_extend_from_slice`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TryExtendError {
+    /// Growing the backing `Vec`'s allocation failed.
+    AllocError(TryReserveError),
+    /// The operation would have exceeded the fixed capacity.
+    CapacityExceeded,
+}
+
+impl fmt::Display for TryExtendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryExtendError::AllocError(err) => write!(f, "allocation failed: {}", err),
+            TryExtendError::CapacityExceeded => write!(f, "would exceed fixed capacity"),
+        }
+    }
 }
 
-impl<T> AsFixedCapacityVec for Vec<T> {
+impl Error for TryExtendError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TryExtendError::AllocError(err) => Some(err),
+            TryExtendError::CapacityExceeded => None,
+        }
+    }
+}
+
+/// Resolves a `RangeBounds<usize>` against a length, the same way slice indexing does.
+///
+/// # Panics
+///
+/// Panics if the range is out of bounds or the start exceeds the end.
+fn resolve_range<R: RangeBounds<usize>>(range: R, len: usize) -> (usize, usize) {
+    let start = match range.start_bound() {
+        Bound::Included(&n) => n,
+        Bound::Excluded(&n) => n + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&n) => n + 1,
+        Bound::Excluded(&n) => n,
+        Bound::Unbounded => len,
+    };
+    assert!(start <= end, "start of range must not exceed its end");
+    assert!(end <= len, "range end out of bounds");
+    (start, end)
+}
+
+impl<T, A: Allocator> AsFixedCapacityVec for Vec<T, A> {
     type Item = T;
+    type Alloc = A;
 
-    fn with_fixed_capacity(&mut self, capacity: usize) -> (&mut [T], FixedCapacityVec<T>) {
+    fn with_fixed_capacity(&mut self, capacity: usize) -> (&mut [T], FixedCapacityVec<T, A>) {
         let len = self.len();
         // Check if we need to allocate more memory
         let free = self.capacity() - len;
         if free < capacity {
-            self.reserve(capacity - free);
+            self.reserve(capacity);
         }
         assert!(self.capacity() - len >= capacity);
 
@@ -109,16 +188,160 @@ impl<T> AsFixedCapacityVec for Vec<T> {
             },
         )
     }
+
+    fn try_with_capacity(
+        &mut self,
+        capacity: usize,
+    ) -> Result<(&mut [T], FixedCapacityVec<T, A>), TryExtendError> {
+        let len = self.len();
+        let free = self.capacity() - len;
+        if free < capacity {
+            self.try_reserve(capacity)
+                .map_err(TryExtendError::AllocError)?;
+        }
+        debug_assert!(self.capacity() - len >= capacity);
+
+        // Vec's internal pointer should always point to a non-null pointer. This is important for
+        // slice's from_raw_parts method.
+        assert!(self.capacity() > 0);
+        let raw_ptr = self.as_mut_ptr();
+        let init_slice = unsafe { slice::from_raw_parts_mut(raw_ptr, len) };
+
+        Ok((
+            init_slice,
+            FixedCapacityVec {
+                start: len,
+                max_len: len + capacity,
+                buffer: self,
+            },
+        ))
+    }
 }
 
-impl<'a, T> FixedCapacityVec<'a, T>
+impl<'a, T, A> FixedCapacityVec<'a, T, A>
 where
-    T: 'a + Clone,
+    T: 'a,
+    A: Allocator,
 {
     fn additional_cap(&self) -> usize {
         self.max_len - self.buffer.len()
     }
 
+    /// Appends an element, returning it back if the fixed capacity has been reached.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fixed_capacity_vec::AsFixedCapacityVec;
+    /// let mut vec = Vec::new();
+    /// let (_, mut extend) = vec.with_fixed_capacity(1);
+    /// assert_eq!(extend.try_push(1), Ok(()));
+    /// assert_eq!(extend.try_push(2), Err(2));
+    /// ```
+    pub fn try_push(&mut self, item: T) -> Result<(), T> {
+        if self.additional_cap() == 0 {
+            return Err(item);
+        }
+        self.buffer.push(item);
+        Ok(())
+    }
+
+    /// Appends an element to the buffer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the fixed capacity has already been reached.
+    pub fn push(&mut self, item: T) {
+        self.try_push(item)
+            .unwrap_or_else(|_| panic!("would exceed fixed capacity"))
+    }
+
+    /// Returns the reserved-but-uninitialized portion of the buffer, i.e. everything between
+    /// the already-written elements and the fixed capacity.
+    ///
+    /// This allows filling the buffer without requiring `T: Clone`, and without the per-element
+    /// capacity check that `push` incurs. Use [`set_len`](Self::set_len)
+    /// to commit the elements once they have been written.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fixed_capacity_vec::AsFixedCapacityVec;
+    /// use std::mem::MaybeUninit;
+    ///
+    /// let mut vec = Vec::new();
+    /// let (_, mut extend) = vec.with_fixed_capacity(2);
+    /// let spare = extend.spare_capacity_mut();
+    /// spare[0] = MaybeUninit::new(1);
+    /// spare[1] = MaybeUninit::new(2);
+    /// unsafe { extend.set_len(2) };
+    /// assert_eq!(extend.as_ref(), &[1, 2]);
+    /// ```
+    pub fn spare_capacity_mut(&mut self) -> &mut [MaybeUninit<T>] {
+        let len = self.buffer.len();
+        let additional = self.additional_cap();
+        unsafe {
+            let ptr = self.buffer.as_mut_ptr().add(len).cast::<MaybeUninit<T>>();
+            slice::from_raw_parts_mut(ptr, additional)
+        }
+    }
+
+    /// Marks `additional` elements of [`spare_capacity_mut`](Self::spare_capacity_mut) as
+    /// initialized, extending the buffer by that many elements.
+    ///
+    /// # Safety
+    ///
+    /// The caller must have initialized the first `additional` elements returned by
+    /// `spare_capacity_mut` before calling this.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `additional` is greater than the remaining capacity.
+    pub unsafe fn set_len(&mut self, additional: usize) {
+        assert!(additional <= self.additional_cap());
+        let new_len = self.buffer.len() + additional;
+        self.buffer.set_len(new_len);
+    }
+
+    /// Extends the buffer from an iterator, returning an error instead of panicking when the
+    /// remaining capacity is exceeded.
+    ///
+    /// The iterator's `size_hint` lower bound is checked up front: if it already exceeds the
+    /// remaining capacity, nothing is pushed and `Err(0)` is returned. Otherwise elements are
+    /// pushed one at a time; if the iterator turns out to yield more than the remaining
+    /// capacity, pushing stops and `Err(number_pushed)` is returned; the elements pushed so far
+    /// stay in the buffer, and the first element that did not fit is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fixed_capacity_vec::AsFixedCapacityVec;
+    /// let mut vec = Vec::new();
+    /// let (_, mut extend) = vec.with_fixed_capacity(2);
+    /// assert_eq!(extend.extend_checked(0..2), Ok(()));
+    /// assert_eq!(extend.extend_checked(0..1), Err(0));
+    /// ```
+    pub fn extend_checked<I: IntoIterator<Item = T>>(&mut self, iter: I) -> Result<(), usize> {
+        let iter = iter.into_iter();
+        let (lower, _) = iter.size_hint();
+        if lower > self.additional_cap() {
+            return Err(0);
+        }
+
+        for (pushed, item) in iter.enumerate() {
+            if self.try_push(item).is_err() {
+                return Err(pushed);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T, A> FixedCapacityVec<'a, T, A>
+where
+    T: 'a + Clone,
+    A: Allocator,
+{
     /// Clones and appends all elements in a slice to the buffer.
     ///
     /// # Panics
@@ -139,20 +362,80 @@ where
     /// assert_eq!(&vec[..], &[1, 2, 3, 4, 5, 6, 7, 8]);
     /// ```
     pub fn extend_from_slice(&mut self, other: &[T]) {
-        assert!(other.len() <= self.additional_cap());
-        self.buffer.extend_from_slice(other)
+        self.try_extend_from_slice(other)
+            .unwrap_or_else(|_| panic!("would exceed fixed capacity"));
     }
 
+    /// Clones and appends all elements in a slice to the buffer, returning the number of
+    /// elements written.
     ///
-    pub fn push(&mut self, item: T) {
-        assert!(self.additional_cap() > 0);
-        self.buffer.push(item)
+    /// # Errors
+    ///
+    /// Returns `Err` without modifying the buffer if `other` would not fit in the remaining
+    /// capacity.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fixed_capacity_vec::AsFixedCapacityVec;
+    /// let mut vec = Vec::new();
+    /// let (_, mut extend) = vec.with_fixed_capacity(2);
+    /// assert_eq!(extend.try_extend_from_slice(&[1, 2, 3]).is_err(), true);
+    /// assert_eq!(extend.try_extend_from_slice(&[1, 2]), Ok(2));
+    /// ```
+    pub fn try_extend_from_slice(&mut self, other: &[T]) -> Result<usize, TryExtendError> {
+        if other.len() > self.additional_cap() {
+            return Err(TryExtendError::CapacityExceeded);
+        }
+        self.buffer.extend_from_slice(other);
+        Ok(other.len())
+    }
+
+    /// Clones and appends the elements of `self[src]` to the end of the buffer, where `src`
+    /// is a range relative to the already-written portion of `self`.
+    ///
+    /// This is useful for LZ-style back-reference copying, since the source range and the
+    /// destination both live in the same backing buffer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `src` is out of bounds, or if the number of elements it selects would exceed
+    /// the remaining capacity.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use fixed_capacity_vec::AsFixedCapacityVec;
+    /// let mut vec = Vec::new();
+    /// {
+    ///     let (_, mut extend) = vec.with_fixed_capacity(6);
+    ///     extend.extend_from_slice(&[1, 2, 3]);
+    ///     extend.extend_from_within(1..);
+    /// }
+    /// assert_eq!(vec, &[1, 2, 3, 2, 3]);
+    /// ```
+    pub fn extend_from_within<R: RangeBounds<usize>>(&mut self, src: R) {
+        let (lo, hi) = resolve_range(src, self.len());
+        let count = hi - lo;
+        assert!(count <= self.additional_cap());
+
+        let start = self.start;
+        unsafe {
+            let src_ptr = self.buffer.as_ptr().add(start + lo);
+            let len = self.buffer.len();
+            let dst_ptr = self.buffer.as_mut_ptr().add(len);
+            for i in 0..count {
+                ptr::write(dst_ptr.add(i), (*src_ptr.add(i)).clone());
+            }
+            self.buffer.set_len(len + count);
+        }
     }
 }
 
-impl<'a, T> Deref for FixedCapacityVec<'a, T>
+impl<'a, T, A> Deref for FixedCapacityVec<'a, T, A>
 where
     T: 'a,
+    A: Allocator,
 {
     type Target = [T];
 
@@ -161,9 +444,10 @@ where
     }
 }
 
-impl<'a, T> DerefMut for FixedCapacityVec<'a, T>
+impl<'a, T, A> DerefMut for FixedCapacityVec<'a, T, A>
 where
     T: 'a,
+    A: Allocator,
 {
     fn deref_mut(&mut self) -> &mut <Self as Deref>::Target {
         let start = self.start;
@@ -171,9 +455,10 @@ where
     }
 }
 
-impl<'a, T> Extend<T> for FixedCapacityVec<'a, T>
+impl<'a, T, A> Extend<T> for FixedCapacityVec<'a, T, A>
 where
     T: 'a + Clone,
+    A: Allocator,
 {
     fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
         for item in iter {
@@ -183,18 +468,20 @@ where
     }
 }
 
-impl<'a, T> AsRef<[T]> for FixedCapacityVec<'a, T>
+impl<'a, T, A> AsRef<[T]> for FixedCapacityVec<'a, T, A>
 where
     T: 'a,
+    A: Allocator,
 {
     fn as_ref(&self) -> &[T] {
         &self[..]
     }
 }
 
-impl<'a, T> AsMut<[T]> for FixedCapacityVec<'a, T>
+impl<'a, T, A> AsMut<[T]> for FixedCapacityVec<'a, T, A>
 where
     T: 'a,
+    A: Allocator,
 {
     fn as_mut(&mut self) -> &mut [T] {
         &mut self[..]
@@ -216,6 +503,16 @@ mod tests {
         assert_eq!(&vec[..], &[9, 9, 9]);
     }
 
+    #[test]
+    fn test_with_fixed_capacity_with_partial_existing_spare() {
+        let mut vec = Vec::with_capacity(4);
+        vec.push(1);
+        vec.push(2);
+        let (_, mut extend) = vec.with_fixed_capacity(4);
+        extend.extend_from_slice(&[3, 4, 5, 6]);
+        assert_eq!(&vec[..], &[1, 2, 3, 4, 5, 6]);
+    }
+
     #[test]
     #[should_panic]
     fn test_over_capacity() {
@@ -248,4 +545,127 @@ mod tests {
         let (_, mut extend) = vec.with_fixed_capacity(2);
         extend.extend(::std::iter::repeat(2).take(3));
     }
+
+    #[test]
+    fn test_try_push() {
+        let mut vec = Vec::new();
+        let (_, mut extend) = vec.with_fixed_capacity(1);
+        assert_eq!(extend.try_push(1), Ok(()));
+        assert_eq!(extend.try_push(2), Err(2));
+    }
+
+    #[test]
+    fn test_try_extend_from_slice() {
+        let mut vec = Vec::new();
+        let (_, mut extend) = vec.with_fixed_capacity(2);
+        assert!(extend.try_extend_from_slice(&[1, 2, 3]).is_err());
+        assert_eq!(extend.try_extend_from_slice(&[1, 2]), Ok(2));
+    }
+
+    #[test]
+    fn test_spare_capacity_mut() {
+        use std::mem::MaybeUninit;
+
+        let mut vec = Vec::new();
+        let (_, mut extend) = vec.with_fixed_capacity(3);
+        {
+            let spare = extend.spare_capacity_mut();
+            assert_eq!(spare.len(), 3);
+            spare[0] = MaybeUninit::new(1);
+            spare[1] = MaybeUninit::new(2);
+        }
+        unsafe { extend.set_len(2) };
+        assert_eq!(extend.as_ref(), &[1, 2]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_set_len_over_cap() {
+        let mut vec: Vec<i32> = Vec::new();
+        let (_, mut extend) = vec.with_fixed_capacity(2);
+        unsafe { extend.set_len(3) };
+    }
+
+    #[test]
+    fn test_extend_from_within() {
+        let mut vec = Vec::new();
+        {
+            let (_, mut extend) = vec.with_fixed_capacity(6);
+            extend.extend_from_slice(&[1, 2, 3]);
+            extend.extend_from_within(1..);
+        }
+        assert_eq!(vec, &[1, 2, 3, 2, 3]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_extend_from_within_over_cap() {
+        let mut vec = Vec::new();
+        let (_, mut extend) = vec.with_fixed_capacity(1);
+        extend.extend_from_slice(&[1]);
+        extend.extend_from_within(..);
+    }
+
+    #[test]
+    fn test_try_with_capacity() {
+        let mut vec: Vec<i32> = Vec::new();
+        let result = vec.try_with_capacity(4);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_try_with_capacity_with_partial_existing_spare() {
+        let mut vec = Vec::with_capacity(4);
+        vec.push(1);
+        vec.push(2);
+        let (_, mut extend) = vec.try_with_capacity(4).unwrap();
+        extend.extend_from_slice(&[3, 4, 5, 6]);
+        assert_eq!(&vec[..], &[1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_custom_allocator_param() {
+        use std::alloc::Global;
+
+        let mut vec: Vec<i32, Global> = Vec::new_in(Global);
+        let (_, mut extend) = vec.with_fixed_capacity(2);
+        extend.extend_from_slice(&[1, 2]);
+        assert_eq!(vec, &[1, 2]);
+    }
+
+    #[test]
+    fn test_extend_checked() {
+        let mut vec = Vec::new();
+        let (_, mut extend) = vec.with_fixed_capacity(2);
+        assert_eq!(extend.extend_checked(0..2), Ok(()));
+        assert_eq!(&vec[..], &[0, 1]);
+    }
+
+    #[test]
+    fn test_extend_checked_size_hint_rejected_up_front() {
+        let mut vec = Vec::new();
+        let (_, mut extend) = vec.with_fixed_capacity(1);
+        assert_eq!(extend.extend_checked(0..2), Err(0));
+        assert_eq!(&vec[..], &[] as &[i32]);
+    }
+
+    #[test]
+    fn test_extend_checked_partial() {
+        // `size_hint` deliberately underestimates so the up-front check passes and the
+        // overrun is only caught while draining the iterator.
+        let mut remaining = 3;
+        let iter = std::iter::from_fn(|| {
+            if remaining == 0 {
+                None
+            } else {
+                remaining -= 1;
+                Some(9)
+            }
+        });
+
+        let mut vec = Vec::new();
+        let (_, mut extend) = vec.with_fixed_capacity(2);
+        assert_eq!(extend.extend_checked(iter), Err(2));
+        assert_eq!(&vec[..], &[9, 9]);
+    }
 }
\ No newline at end of file